@@ -2,13 +2,20 @@
 #[macro_use] extern crate rocket;
 
 // Channels pass messages in between different async tasks
-use rocket::{State, Shutdown};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use rocket::{State, Shutdown, Request};
 use rocket::fs::{relative, FileServer};
 use rocket::form::Form;
+use rocket::request::{FromRequest, Outcome};
 use rocket::response::stream::{EventStream, Event};
+use rocket::serde::json::Json;
 use rocket::serde::{Serialize, Deserialize};
 use rocket::tokio::sync::broadcast::{channel, Sender, error::RecvError};
 use rocket::tokio::select;
+use rocket::tokio::time::{sleep, Duration};
 
 #[derive(Debug, Clone, FromForm, Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
@@ -18,19 +25,258 @@ struct Message {
     #[field(validate = len(..20))]
     pub username: String,
     pub message: String,
+    #[field(validate = len(..20))]
+    pub to: Option<String>,
+}
+
+// A message is visible to `username` if it isn't a DM, if it's the DM's
+// recipient, or if it's the DM's own sender (so senders see their own
+// messages echoed back).
+fn visible_to(msg: &Message, username: &str) -> bool {
+    match &msg.to {
+        Some(recipient) => recipient == username || msg.username == username,
+        None => true,
+    }
+}
+
+// Sent as a named "lag" SSE event whenever a subscriber falls far enough
+// behind that the broadcast channel drops messages out from under it, so
+// the front-end can show a "you missed N messages" marker instead of a
+// silent gap.
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct LagNotice {
+    room: String,
+    skipped: u64,
+}
+
+// Sent when a reconnecting client's `Last-Event-ID` is older than anything
+// left in the room's history buffer, so it knows the replay it's about to
+// receive doesn't cover the entire gap.
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct HistoryTruncated {
+    room: String,
+}
+
+// Configured broadcast channel capacity, read from `chat.capacity` in
+// Rocket.toml (or a `ROCKET_CHAT={capacity=N}` env var). Also doubles as how many
+// messages each room's history buffer retains for reconnecting clients,
+// since there's no point keeping more history than the channel itself
+// could ever have in flight at once.
+struct Capacity(usize);
+
+// Running count of `Lagged` events observed across every room, exposed via
+// `/metrics` so operators can tell when subscribers are falling behind
+// before messages start being silently dropped.
+struct LaggedTotal(AtomicU64);
+
+// A room's broadcast channel paired with a bounded replay buffer. Every
+// message is tagged with a monotonic sequence number before it's sent, so
+// a reconnecting `EventSource` (which sends back the last id it saw via
+// `Last-Event-ID`) can be caught up on exactly what it missed.
+struct Room {
+    tx: Sender<(u64, Message)>,
+    history: Mutex<History>,
+    capacity: usize,
+}
+
+struct History {
+    next_seq: u64,
+    buffer: VecDeque<(u64, Message)>,
+}
+
+// Each room gets its own broadcast channel, so a client subscribed to
+// one room is never woken up (or forced to filter client-side) for
+// traffic in any other room. Wrapped in an `Arc` (on top of the `Arc`
+// Rocket already holds the managed state behind) so a disconnecting
+// subscriber's cleanup task can carry its own handle to the map into a
+// detached `tokio::spawn`, which requires 'static data.
+type Rooms = Arc<Mutex<HashMap<String, Arc<Room>>>>;
+
+// How long an empty room is kept around before `schedule_eviction` removes
+// it, long enough to absorb the brief gap a reconnecting `EventSource`
+// leaves behind (it drops the old connection and opens a new one, so
+// `receiver_count()` dips to zero in between) without letting rooms nobody
+// has touched in a while pile up in the map forever.
+const ROOM_EVICTION_GRACE: Duration = Duration::from_secs(30);
+
+// Looks up the room, creating a fresh channel and history buffer for it
+// on first use. An existing entry is always reused as-is, even with no
+// current subscribers: replacing it here would reset its sequence counter
+// and wipe its history buffer out from under the reconnect that is about
+// to ask for both (see `schedule_eviction` for how empty rooms actually
+// get cleaned up instead).
+fn room_entry(rooms: &Rooms, room: &str, capacity: usize) -> Arc<Room> {
+    let mut rooms = rooms.lock().unwrap();
+
+    if let Some(entry) = rooms.get(room) {
+        return entry.clone();
+    }
+
+    let (tx, _rx) = channel(capacity);
+    let entry = Arc::new(Room {
+        tx,
+        history: Mutex::new(History { next_seq: 0, buffer: VecDeque::new() }),
+        capacity,
+    });
+    rooms.insert(room.to_string(), entry.clone());
+    entry
+}
+
+// Runs after a subscriber's stream ends, detached from the request so the
+// disconnect doesn't wait on it. Waits out `ROOM_EVICTION_GRACE` in case
+// this was just an `EventSource` auto-reconnect, then removes the room
+// from the map only if it's still the very same entry (a reconnect in the
+// meantime would have reused it, per `room_entry`) and still has no
+// subscribers. This is what actually bounds the map's growth in the
+// number of distinct rooms ever used, without racing a fast reconnect into
+// recreating the room from scratch and losing its history/sequence
+// counter.
+fn schedule_eviction(rooms: Rooms, room_name: String, room: Arc<Room>) {
+    rocket::tokio::spawn(async move {
+        sleep(ROOM_EVICTION_GRACE).await;
+        let mut rooms = rooms.lock().unwrap();
+        if let Some(current) = rooms.get(&room_name) {
+            if Arc::ptr_eq(current, &room) && current.tx.receiver_count() == 0 {
+                rooms.remove(&room_name);
+            }
+        }
+    });
+}
+
+// Held by a subscriber's stream for its whole lifetime and dropped when the
+// stream is, which happens whether it ends cleanly (`Closed`/`Shutdown`) or
+// is abandoned mid-poll because the client just disconnected — unlike code
+// placed after the stream's loop, a `Drop` impl runs in both cases, so this
+// is the only reliable place to hook eviction.
+struct EvictOnDrop {
+    rooms: Rooms,
+    room_name: String,
+    room: Arc<Room>,
+}
+
+impl Drop for EvictOnDrop {
+    fn drop(&mut self) {
+        schedule_eviction(self.rooms.clone(), self.room_name.clone(), self.room.clone());
+    }
+}
+
+// Snapshot of broadcast backpressure across every room, for operators to
+// size `chat.capacity` against their actual load.
+#[derive(Debug, Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+struct Metrics {
+    capacity: usize,
+    receiver_count: usize,
+    pending: usize,
+    lagged_total: u64,
+}
+
+#[get("/metrics")]
+fn metrics(rooms: &State<Rooms>, capacity: &State<Capacity>, lagged_total: &State<LaggedTotal>) -> Json<Metrics> {
+    let rooms = rooms.lock().unwrap();
+    Json(Metrics {
+        capacity: capacity.0,
+        receiver_count: rooms.values().map(|room| room.tx.receiver_count()).sum(),
+        pending: rooms.values().map(|room| room.tx.len()).sum(),
+        lagged_total: lagged_total.0.load(Ordering::Relaxed),
+    })
+}
+
+// Request guard that reads the SSE `Last-Event-ID` header the browser
+// automatically resends when `EventSource` reconnects after a drop.
+struct LastEventId(Option<u64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let id = req.headers().get_one("Last-Event-ID").and_then(|id| id.parse().ok());
+        Outcome::Success(LastEventId(id))
+    }
 }
 
 // EventStreams are similar to web sockets, but work in one direction
-#[get("/events")]
-async fn events(queue: &State<Sender<Message>>, mut end: Shutdown) -> EventStream![] {
-    // New receiver
-    let mut rx = queue.subscribe();
+#[get("/events/<room>?<username>")]
+async fn events(
+    room: &str,
+    username: &str,
+    rooms: &State<Rooms>,
+    capacity: &State<Capacity>,
+    lagged_total: &State<LaggedTotal>,
+    last_event_id: LastEventId,
+    mut end: Shutdown,
+) -> EventStream![] {
+    let room_name = room.to_string();
+    let username = username.to_string();
+    let room = room_entry(rooms, room, capacity.0);
+    // Owned handle to the map, carried into the cleanup task scheduled once
+    // this subscriber's stream ends (a detached `tokio::spawn` needs 'static
+    // data, which the request-scoped `&State<Rooms>` above isn't).
+    let rooms = rooms.inner().clone();
+
+    // Subscribe and snapshot the replay backlog under the same history
+    // lock that `post` pushes and broadcasts under (see `post`), so a
+    // message posted right around now lands on exactly one side of the
+    // join: if this lock wins the race, `post` can't proceed until we've
+    // subscribed, so its message only ever arrives live; if `post` already
+    // finished, it's in the buffer snapshotted here and never reaches the
+    // new receiver (so it's replayed at most once, and only if the client
+    // sent a `Last-Event-ID` older than it). Subscribing outside
+    // the lock (as before) can't make that guarantee - a message already
+    // pushed into the buffer but not yet broadcast could still land in the
+    // new receiver too, delivering it twice.
+    let (mut rx, backlog) = {
+        let history = room.history.lock().unwrap();
+        // If the client told us what it last saw, work out what to replay
+        // before joining the live stream, so a reconnecting `EventSource`
+        // doesn't lose messages sent during the gap. DMs not addressed to
+        // (or sent by) this subscriber are dropped from the replay just
+        // like they are from the live loop below.
+        let backlog = last_event_id.0.map(|last_id| {
+            let truncated = match history.buffer.front() {
+                Some((oldest, _)) => last_id < oldest.saturating_sub(1),
+                // An empty buffer on a room the client already has a
+                // `Last-Event-ID` for isn't proof nothing was missed: long
+                // enough idle and `schedule_eviction` recycles the room
+                // entirely, so this could just as easily be a fresh
+                // incarnation that inherited none of the old one's
+                // history. Assume the worst rather than silently resuming
+                // as if this were a brand new session.
+                None => true,
+            };
+            let replay: Vec<(u64, Message)> = history.buffer.iter()
+                .filter(|(id, msg)| (truncated || *id > last_id) && visible_to(msg, &username))
+                .cloned()
+                .collect();
+            (truncated, replay)
+        });
+        (room.tx.subscribe(), backlog)
+    };
 
     // Server sent events are produced asynchronously
     // Shutdown resolves after the server is shutdown
 
     // Infinite series of server events
     EventStream! {
+        // Owned by the stream itself, so it drops (and schedules eviction)
+        // whenever the stream does, including a client disconnect that
+        // abandons the generator mid-poll rather than reaching a `break`.
+        let _evict_guard = EvictOnDrop { rooms, room_name: room_name.clone(), room };
+
+        // Replay missed history before entering the live loop below.
+        if let Some((truncated, replay)) = backlog {
+            if truncated {
+                let notice = HistoryTruncated { room: room_name.clone() };
+                yield Event::json(&notice).event("history-truncated");
+            }
+            for (seq, msg) in replay {
+                yield Event::json(&msg).id(seq.to_string());
+            }
+        }
+
         loop {
             // Select waits on multiple concurrent branches and returns once one completes
             let msg = select! {
@@ -41,36 +287,87 @@ async fn events(queue: &State<Sender<Message>>, mut end: Shutdown) -> EventStrea
                     Ok(msg) => msg,
                     // No more senders, break loop
                     Err(RecvError::Closed) => break,
-                    // Receiver lagged too far behind, and was disconnected
-                    // Next iteration of loop is then ran
-                    Err(RecvError::Lagged(_)) => continue,
+                    // Receiver lagged too far behind and missed some messages.
+                    // Tell the client how many it missed, then resume the loop
+                    // from the top rather than yielding a `Message`.
+                    Err(RecvError::Lagged(skipped)) => {
+                        lagged_total.0.fetch_add(1, Ordering::Relaxed);
+                        let notice = LagNotice { room: room_name.clone(), skipped };
+                        yield Event::json(&notice).event("lag");
+                        continue;
+                    }
                 },
                 // Waits for shutdown feature to resolve, breaking the infinite loop
                 _ = &mut end => break,
             };
+            // DMs not addressed to (or sent by) this subscriber don't exist
+            // as far as it's concerned; skip straight to the next message
+            // rather than yielding it.
+            if !visible_to(&msg.1, &username) {
+                continue;
+            }
             // If no break/error was hit, the select macro returns the message we got from receiver
             // Yield a new server sent event with the new message
-            yield Event::json(&msg);
+            yield Event::json(&msg.1).id(msg.0.to_string());
         }
     }
 }
 
 #[post("/message", data = "<form>")]
-fn post(form: Form<Message>, queue: &State<Sender<Message>>) {
+fn post(form: Form<Message>, rooms: &State<Rooms>, capacity: &State<Capacity>) {
+    let room = room_entry(rooms, &form.room, capacity.0);
+    let msg = form.into_inner();
+    let room_name = msg.room.clone();
+
+    // Push into the history buffer and broadcast under the same lock, so
+    // replay always sees a buffer whose ids line up with what was actually
+    // broadcast, and so a subscriber that's mid-subscribe (see `events`)
+    // can never see this message land in both its history snapshot and
+    // its live receiver.
+    let mut history = room.history.lock().unwrap();
+    history.next_seq += 1;
+    let seq = history.next_seq;
+    history.buffer.push_back((seq, msg.clone()));
+    if history.buffer.len() > room.capacity {
+        history.buffer.pop_front();
+    }
+
     // A send 'fails' if there are no active subscribers. That's okay
-    let _res = queue.send(form.into_inner());
+    let _res = room.tx.send((seq, msg));
+    drop(history);
+
+    // A post can create a room (via `room_entry` above) that nobody has
+    // ever subscribed to, or outlast its last subscriber without anyone
+    // reconnecting - `EvictOnDrop` only runs for the latter, so without
+    // this, posting to enough distinct room names alone grows the map
+    // without bound.
+    if room.tx.receiver_count() == 0 {
+        schedule_eviction(rooms.inner().clone(), room_name, room);
+    }
 }
 
 
 #[launch]
 fn rocket() -> _ {
-    rocket::build()
-    // Add state to the server of our rocket instance, 
+    let rocket = rocket::build();
+
+    // `chat.capacity` in Rocket.toml (or `ROCKET_CHAT={capacity=N}` in the
+    // environment) controls how large each room's broadcast channel and
+    // history buffer is. Falls back to the previous hard-coded 1024 so
+    // existing deployments behave the same without a config change.
+    // Clamped to at least 1: `channel(0)` panics, and a bad config value
+    // shouldn't be able to take the server down on the first `post`.
+    let capacity: usize = rocket.figment().extract_inner("chat.capacity").unwrap_or(1024).max(1);
+
+    rocket
+    // Add state to the server of our rocket instance,
     // which all rocket access handlers have access to
-    // What type of messages? A message struct. 
-    // Amount of messages a channel can retain at a given time: 1024
-    // The output of the channel function is a tuple containing sender & receiver
-    .manage(channel::<Message>(1024).0)
-    .mount("/", routes![post, events])
+    // Rooms are created lazily, each with their own broadcast channel
+    // and history buffer, so every room keeps its own `capacity`-sized
+    // backlog.
+    .manage(Rooms::new(Mutex::new(HashMap::new())))
+    .manage(Capacity(capacity))
+    .manage(LaggedTotal(AtomicU64::new(0)))
+    .mount("/", routes![post, events, metrics])
     .mount("/", FileServer::from(relative!("static")))
-}
\ No newline at end of file
+}